@@ -4,11 +4,23 @@ use esp_idf_hal::gpio::*;
 use esp_idf_hal::prelude::*;
 use esp_idf_svc::log::EspLogger;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sntp::{EspSntp, SyncStatus};
 use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use esp_idf_sys::{
+    esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition,
+    esp_ota_handle_t, esp_ota_mark_app_valid_cancel_rollback, esp_ota_set_boot_partition,
+    esp_ota_write, esp_restart, OTA_SIZE_UNKNOWN,
+};
 use log::*;
-use onewire::{Device, DeviceSearch, OneWire};
-use serde_json::json;
+use onewire::{DeviceSearch, OneWire};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use alloc::string::ToString;
 use alloc::ffi::CString;
 
@@ -30,34 +42,222 @@ fn ms_to_ticks(ms: u32) -> u32 {
     (ms as u64 * esp_idf_sys::configTICK_RATE_HZ as u64 / 1000) as u32
 }
 
+// Render a DS18B20 64-bit ROM code the way the datasheet/ThingsBoard keys
+// expect it, e.g. "28ff641e0416036a".
+fn rom_to_hex(rom: &[u8; 8]) -> String {
+    rom.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Last (topic, payload) delivered to MQTT_EVENT_DATA, picked up by whichever
+// blocking call is waiting on a reply (attributes response, OTA chunk, ...).
+static MQTT_INBOX: Mutex<Option<(String, Vec<u8>)>> = Mutex::new(None);
+
+// In-progress reassembly of a single MQTT_EVENT_DATA message: esp-mqtt
+// fragments any payload bigger than its internal buffer (far smaller than
+// OTA_CHUNK_SIZE) across several callbacks, using current_data_offset and
+// total_data_len to mark the pieces. Only the first fragment carries the
+// topic, so it's stashed here until the last fragment arrives.
+static MQTT_REASSEMBLY: Mutex<Option<(String, Vec<u8>)>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum MqttConnState {
+    Connecting,
+    Connected,
+    Disconnected,
+    Error,
+}
+
+// Tracks the outcome of the handshake so `SimpleMqttClient::new` can report a
+// real TLS/connect error instead of just hoping 5 seconds was long enough.
+static MQTT_CONN_STATE: Mutex<MqttConnState> = Mutex::new(MqttConnState::Connecting);
+
+unsafe extern "C" fn mqtt_event_handler(
+    _handler_args: *mut core::ffi::c_void,
+    _base: esp_idf_sys::esp_event_base_t,
+    event_id: i32,
+    event_data: *mut core::ffi::c_void,
+) {
+    let event = &*(event_data as *const esp_idf_sys::esp_mqtt_event_t);
+
+    if event_id == esp_idf_sys::esp_mqtt_event_id_t_MQTT_EVENT_CONNECTED as i32 {
+        if let Ok(mut state) = MQTT_CONN_STATE.lock() {
+            *state = MqttConnState::Connected;
+        }
+        return;
+    }
+
+    if event_id == esp_idf_sys::esp_mqtt_event_id_t_MQTT_EVENT_DISCONNECTED as i32 {
+        if let Ok(mut state) = MQTT_CONN_STATE.lock() {
+            *state = MqttConnState::Disconnected;
+        }
+        return;
+    }
+
+    if event_id == esp_idf_sys::esp_mqtt_event_id_t_MQTT_EVENT_ERROR as i32 {
+        if !event.error_handle.is_null() {
+            let err = &*event.error_handle;
+            error!(
+                "❌ MQTT/TLS error: type={} tls_last_esp_err={} tls_stack_err={}",
+                err.error_type, err.esp_tls_last_esp_err, err.esp_tls_stack_err
+            );
+        }
+        if let Ok(mut state) = MQTT_CONN_STATE.lock() {
+            *state = MqttConnState::Error;
+        }
+        return;
+    }
+
+    if event_id != esp_idf_sys::esp_mqtt_event_id_t_MQTT_EVENT_DATA as i32 {
+        return;
+    }
+    let data =
+        core::slice::from_raw_parts(event.data as *const u8, event.data_len as usize).to_vec();
+
+    let Ok(mut reassembly) = MQTT_REASSEMBLY.lock() else {
+        return;
+    };
+
+    if event.current_data_offset == 0 {
+        let topic = core::slice::from_raw_parts(event.topic as *const u8, event.topic_len as usize);
+        let topic = String::from_utf8_lossy(topic).to_string();
+        let mut buf = Vec::with_capacity(event.total_data_len as usize);
+        buf.extend_from_slice(&data);
+        *reassembly = Some((topic, buf));
+    } else if let Some((_, buf)) = reassembly.as_mut() {
+        buf.extend_from_slice(&data);
+    } else {
+        // Missed the first fragment (which carries the topic) for this
+        // message; nothing sane to reassemble into, drop it.
+        return;
+    }
+
+    let complete = reassembly
+        .as_ref()
+        .map(|(_, buf)| buf.len() as i32 >= event.total_data_len)
+        .unwrap_or(false);
+    if !complete {
+        return;
+    }
+
+    if let Some((topic, payload)) = reassembly.take() {
+        if let Ok(mut inbox) = MQTT_INBOX.lock() {
+            *inbox = Some((topic, payload));
+        }
+    }
+}
+
+// Block (with timeout) until a message whose topic starts with `prefix`
+// lands in the inbox, then return its payload.
+fn wait_for_topic_prefix(prefix: &str, timeout: Duration) -> Option<Vec<u8>> {
+    let start = Instant::now();
+    loop {
+        if let Ok(mut inbox) = MQTT_INBOX.lock() {
+            if let Some((topic, _)) = inbox.as_ref() {
+                if topic.starts_with(prefix) {
+                    let (_, data) = inbox.take().unwrap();
+                    return Some(data);
+                }
+            }
+        }
+        if start.elapsed() > timeout {
+            return None;
+        }
+        esp_idf_hal::delay::FreeRtos::delay_ms(50);
+    }
+}
+
+// How the MQTT connection to the broker is secured. `Plain` isn't
+// constructed anywhere today since ThingsBoard Cloud requires TLS, but it's
+// part of the intended public shape of this enum for a future local/dev
+// broker that doesn't — keep it instead of shrinking the interface.
+#[allow(dead_code)]
+enum Transport<'a> {
+    Plain,
+    Tls {
+        // PEM-encoded CA bundle embedded in the firmware image, used to verify
+        // the broker's certificate chain.
+        ca_pem: &'a str,
+        // Skip checking the certificate's CN/SAN against the broker hostname;
+        // only useful when talking to a broker behind an IP or a CDN.
+        skip_cn_check: bool,
+        // Optional client certificate/key for mutual TLS.
+        client_cert_pem: Option<&'a str>,
+        client_key_pem: Option<&'a str>,
+    },
+}
+
+const MQTT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 // MQTT client wrapper
 struct SimpleMqttClient {
     client: *mut esp_idf_sys::esp_mqtt_client,
 }
 
 impl SimpleMqttClient {
-    fn new(broker_url: &str, username: &str, password: &str, client_id: &str) -> Result<Self> {
+    fn new(
+        broker_url: &str,
+        username: &str,
+        password: &str,
+        client_id: &str,
+        transport: Transport,
+    ) -> Result<Self> {
         unsafe {
             let broker_url_cstr = CString::new(broker_url)?;
             let username_cstr = CString::new(username)?;
             let password_cstr = CString::new(password)?;
             let client_id_cstr = CString::new(client_id)?;
 
+            // Kept alive for the duration of esp_mqtt_client_init below.
+            let ca_cstr;
+            let client_cert_cstr;
+            let client_key_cstr;
+            let mut verification = esp_idf_sys::esp_mqtt_client_config_t_broker_t_verification_t {
+                ..core::mem::zeroed()
+            };
+            let mut authentication = esp_idf_sys::esp_mqtt_client_config_t_credentials_t_authentication_t {
+                password: password_cstr.as_ptr() as *const u8,
+                ..core::mem::zeroed()
+            };
+
+            if let Transport::Tls {
+                ca_pem,
+                skip_cn_check,
+                client_cert_pem,
+                client_key_pem,
+            } = transport
+            {
+                ca_cstr = Some(CString::new(ca_pem)?);
+                verification.certificate = ca_cstr.as_ref().unwrap().as_ptr() as *const u8;
+                verification.skip_cert_common_name_check = skip_cn_check;
+
+                if let (Some(cert_pem), Some(key_pem)) = (client_cert_pem, client_key_pem) {
+                    client_cert_cstr = Some(CString::new(cert_pem)?);
+                    client_key_cstr = Some(CString::new(key_pem)?);
+                    authentication.certificate = client_cert_cstr.as_ref().unwrap().as_ptr() as *const u8;
+                    authentication.key = client_key_cstr.as_ref().unwrap().as_ptr() as *const u8;
+                } else {
+                    client_cert_cstr = None;
+                    client_key_cstr = None;
+                }
+            } else {
+                ca_cstr = None;
+                client_cert_cstr = None;
+                client_key_cstr = None;
+            }
+
             let config = esp_idf_sys::esp_mqtt_client_config_t {
                 broker: esp_idf_sys::esp_mqtt_client_config_t_broker_t {
                     address: esp_idf_sys::esp_mqtt_client_config_t_broker_t_address_t {
                         uri: broker_url_cstr.as_ptr() as *const u8,
                         ..core::mem::zeroed()
                     },
+                    verification,
                     ..core::mem::zeroed()
                 },
                 credentials: esp_idf_sys::esp_mqtt_client_config_t_credentials_t {
                     username: username_cstr.as_ptr() as *const u8,
                     client_id: client_id_cstr.as_ptr() as *const u8,
-                    authentication: esp_idf_sys::esp_mqtt_client_config_t_credentials_t_authentication_t {
-                        password: password_cstr.as_ptr() as *const u8,
-                        ..core::mem::zeroed()
-                    },
+                    authentication,
                     ..core::mem::zeroed()
                 },
                 ..core::mem::zeroed()
@@ -68,12 +268,46 @@ impl SimpleMqttClient {
                 return Err(anyhow::anyhow!("Failed to initialize MQTT client"));
             }
 
+            if let Ok(mut state) = MQTT_CONN_STATE.lock() {
+                *state = MqttConnState::Connecting;
+            }
+
+            let err = esp_idf_sys::esp_mqtt_client_register_event(
+                client,
+                esp_idf_sys::esp_mqtt_event_id_t_MQTT_EVENT_ANY,
+                Some(mqtt_event_handler),
+                core::ptr::null_mut(),
+            );
+            if err != esp_idf_sys::ESP_OK {
+                return Err(anyhow::anyhow!("Failed to register MQTT event handler, error code: {}", err));
+            }
+
             let err = esp_idf_sys::esp_mqtt_client_start(client);
             if err != esp_idf_sys::ESP_OK {
                 return Err(anyhow::anyhow!("Failed to start MQTT client, error code: {}", err));
             }
 
-            esp_idf_sys::vTaskDelay(ms_to_ticks(5000));
+            let start = Instant::now();
+            loop {
+                let state = MQTT_CONN_STATE.lock().map(|s| *s).unwrap_or(MqttConnState::Connecting);
+                match state {
+                    MqttConnState::Connected => break,
+                    MqttConnState::Error => {
+                        esp_idf_sys::esp_mqtt_client_stop(client);
+                        esp_idf_sys::esp_mqtt_client_destroy(client);
+                        return Err(anyhow::anyhow!("MQTT/TLS handshake failed, see logs above"));
+                    }
+                    MqttConnState::Connecting | MqttConnState::Disconnected => {
+                        if start.elapsed() > MQTT_CONNECT_TIMEOUT {
+                            esp_idf_sys::esp_mqtt_client_stop(client);
+                            esp_idf_sys::esp_mqtt_client_destroy(client);
+                            return Err(anyhow::anyhow!("Timed out waiting for MQTT connection"));
+                        }
+                        esp_idf_hal::delay::FreeRtos::delay_ms(50);
+                    }
+                }
+            }
+
             Ok(Self { client })
         }
     }
@@ -103,6 +337,24 @@ impl SimpleMqttClient {
             }
         }
     }
+
+    fn subscribe(&self, topic: &str) -> Result<()> {
+        unsafe {
+            let topic_cstr = CString::new(topic)?;
+            let msg_id = esp_idf_sys::esp_mqtt_client_subscribe(self.client, topic_cstr.as_ptr(), 1);
+
+            if msg_id < 0 {
+                Err(anyhow::anyhow!(
+                    "Failed to subscribe to {}, error code: {}",
+                    topic,
+                    msg_id
+                ))
+            } else {
+                info!("Subscribed to {} (msg id {})", topic, msg_id);
+                Ok(())
+            }
+        }
+    }
 }
 
 impl Drop for SimpleMqttClient {
@@ -117,6 +369,35 @@ impl Drop for SimpleMqttClient {
 // ====================================================
 // 🧩 OTA Update Functions
 // ====================================================
+// ThingsBoard Cloud's TLS endpoint (port 8883) serves a certificate chaining
+// to ISRG Root X1; swap this file out when pointing at a self-hosted broker.
+const THINGSBOARD_CA_PEM: &str = include_str!("certs/isrg_root_x1.pem");
+
+const FW_TITLE: &str = "ds18b20-mqtt";
+const FW_VERSION: &str = "1.0.0";
+const OTA_CHUNK_SIZE: usize = 4096;
+const OTA_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+struct FirmwareAttributes {
+    fw_title: String,
+    fw_version: String,
+    fw_checksum: String,
+    fw_checksum_algorithm: String,
+    fw_size: usize,
+}
+
+// ThingsBoard wraps an attributes-request response as
+// `{"client":{...},"shared":{...}}`, only including whichever key was
+// actually requested. We only ever ask for shared keys, so `client` is
+// absent from a real response; default it instead of requiring it.
+#[derive(Deserialize, Debug)]
+struct AttributesResponse {
+    #[serde(default)]
+    client: Value,
+    shared: FirmwareAttributes,
+}
+
 fn send_ota_status(mqtt_client: &SimpleMqttClient, title: &str, version: &str, state: &str) -> Result<()> {
     let payload = json!({
         "fw_title": title,
@@ -130,22 +411,424 @@ fn send_ota_status(mqtt_client: &SimpleMqttClient, title: &str, version: &str, s
     Ok(())
 }
 
-// Function to send temperature data to ThingsBoard
-fn send_telemetry(mqtt_client: &SimpleMqttClient, temperature: f32) -> Result<()> {
-    let payload = json!({
-        "temperature": temperature
-    })
-    .to_string();
+// Ask ThingsBoard for the current shared attributes and, if firmware for
+// FW_TITLE is published with a version different from FW_VERSION, download
+// and flash it over MQTT.
+fn check_and_apply_ota(mqtt_client: &SimpleMqttClient) -> Result<()> {
+    mqtt_client.subscribe("v1/devices/me/attributes")?;
+    mqtt_client.subscribe("v1/devices/me/attributes/response/+")?;
+    mqtt_client.publish(
+        "v1/devices/me/attributes/request/1",
+        &json!({ "sharedKeys": "fw_title,fw_version,fw_checksum,fw_checksum_algorithm,fw_size" }).to_string(),
+    )?;
 
-    mqtt_client.publish("v1/devices/me/telemetry", &payload)?;
-    info!("Data sent to ThingsBoard: {}", payload);
+    let raw = match wait_for_topic_prefix("v1/devices/me/attributes", OTA_REQUEST_TIMEOUT) {
+        Some(raw) => raw,
+        None => {
+            warn!("No firmware attributes received, skipping OTA check");
+            return Ok(());
+        }
+    };
+
+    let attrs: FirmwareAttributes = match serde_json::from_slice::<AttributesResponse>(&raw) {
+        Ok(resp) => resp.shared,
+        Err(e) => {
+            warn!("Firmware attributes malformed, skipping OTA check: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    if attrs.fw_title != FW_TITLE || attrs.fw_version == FW_VERSION {
+        info!("Firmware up to date ({} {})", FW_TITLE, FW_VERSION);
+        return Ok(());
+    }
+
+    info!(
+        "🆕 New firmware available: {} {} -> {}",
+        attrs.fw_title, FW_VERSION, attrs.fw_version
+    );
+    if let Err(e) = apply_ota_update(mqtt_client, &attrs) {
+        error!("OTA update failed: {:?}", e);
+        send_ota_status(mqtt_client, &attrs.fw_title, &attrs.fw_version, "FAILED")?;
+    }
+    Ok(())
+}
+
+// Stream the firmware image in fixed-size chunks, write it straight into the
+// inactive OTA partition and reboot into it once the checksum checks out.
+fn apply_ota_update(mqtt_client: &SimpleMqttClient, attrs: &FirmwareAttributes) -> Result<()> {
+    let request_id = 0u32;
+    let chunk_request_topic = format!("v2/fw/request/{}/chunk/", request_id);
+    let chunk_response_prefix = format!("v2/fw/response/{}/chunk/", request_id);
+    mqtt_client.subscribe(&format!("{}+", chunk_response_prefix))?;
+
+    send_ota_status(mqtt_client, &attrs.fw_title, &attrs.fw_version, "DOWNLOADING")?;
+
+    let update_partition = unsafe { esp_ota_get_next_update_partition(core::ptr::null()) };
+    if update_partition.is_null() {
+        return Err(anyhow::anyhow!("No OTA update partition available"));
+    }
+
+    let mut handle: esp_ota_handle_t = 0;
+    let err = unsafe { esp_ota_begin(update_partition, OTA_SIZE_UNKNOWN as usize, &mut handle) };
+    if err != esp_idf_sys::ESP_OK {
+        return Err(anyhow::anyhow!("esp_ota_begin failed: {}", err));
+    }
+
+    let mut hasher = Sha256::new();
+    let mut received = 0usize;
+    let mut chunk_id: u32 = 0;
+
+    loop {
+        mqtt_client.publish(
+            &format!("{}{}", chunk_request_topic, chunk_id),
+            &OTA_CHUNK_SIZE.to_string(),
+        )?;
+
+        let chunk = match wait_for_topic_prefix(&chunk_response_prefix, OTA_REQUEST_TIMEOUT) {
+            Some(chunk) => chunk,
+            None => {
+                unsafe { esp_ota_abort(handle) };
+                return Err(anyhow::anyhow!("Timed out waiting for OTA chunk {}", chunk_id));
+            }
+        };
+
+        if chunk.is_empty() {
+            break;
+        }
+
+        let err = unsafe {
+            esp_ota_write(handle, chunk.as_ptr() as *const core::ffi::c_void, chunk.len() as u32)
+        };
+        if err != esp_idf_sys::ESP_OK {
+            unsafe { esp_ota_abort(handle) };
+            return Err(anyhow::anyhow!("esp_ota_write failed at chunk {}: {}", chunk_id, err));
+        }
+
+        hasher.update(&chunk);
+        received += chunk.len();
+        chunk_id += 1;
+
+        if received >= attrs.fw_size {
+            break;
+        }
+    }
+
+    send_ota_status(mqtt_client, &attrs.fw_title, &attrs.fw_version, "DOWNLOADED")?;
+
+    if !attrs.fw_checksum_algorithm.eq_ignore_ascii_case("SHA256") {
+        unsafe { esp_ota_abort(handle) };
+        return Err(anyhow::anyhow!(
+            "Unsupported checksum algorithm: {}",
+            attrs.fw_checksum_algorithm
+        ));
+    }
+    let digest_hex = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    if digest_hex != attrs.fw_checksum.to_lowercase() {
+        unsafe { esp_ota_abort(handle) };
+        return Err(anyhow::anyhow!(
+            "Checksum mismatch: expected {} got {}",
+            attrs.fw_checksum,
+            digest_hex
+        ));
+    }
+    send_ota_status(mqtt_client, &attrs.fw_title, &attrs.fw_version, "VERIFIED")?;
+
+    let err = unsafe { esp_ota_end(handle) };
+    if err != esp_idf_sys::ESP_OK {
+        return Err(anyhow::anyhow!("esp_ota_end failed: {}", err));
+    }
+
+    send_ota_status(mqtt_client, &attrs.fw_title, &attrs.fw_version, "UPDATING")?;
+    let err = unsafe { esp_ota_set_boot_partition(update_partition) };
+    if err != esp_idf_sys::ESP_OK {
+        return Err(anyhow::anyhow!("esp_ota_set_boot_partition failed: {}", err));
+    }
+
+    send_ota_status(mqtt_client, &attrs.fw_title, &attrs.fw_version, "UPDATED")?;
+    info!("🔄 Rebooting into new firmware {}", attrs.fw_version);
+    unsafe { esp_restart() };
+}
+
+// Check the inbox for at most one pending unsolicited shared-attribute push
+// (e.g. an operator editing fw_version from the ThingsBoard dashboard) and,
+// if one arrived, re-run the OTA check so a new firmware push takes effect
+// without waiting for a reboot. Non-blocking: a no-op when nothing is
+// pending.
+fn poll_shared_attribute_updates(mqtt_client: &SimpleMqttClient) -> Result<()> {
+    {
+        let mut inbox = MQTT_INBOX.lock().unwrap();
+        match inbox.as_ref() {
+            Some((topic, _)) if topic == "v1/devices/me/attributes" => {
+                inbox.take();
+            }
+            _ => return Ok(()),
+        }
+    }
+
+    info!("📥 Shared attribute update pushed, re-checking firmware version");
+    check_and_apply_ota(mqtt_client)
+}
+
+// ====================================================
+// 📟 Server-to-device RPC
+// ====================================================
+type RpcHandler = Box<dyn Fn(Value) -> Value + Send + Sync>;
+
+const RPC_REQUEST_PREFIX: &str = "v1/devices/me/rpc/request/";
+
+// Check the inbox for at most one pending RPC request and dispatch it through
+// the handler table, publishing the handler's return value back to
+// ThingsBoard. Non-blocking: a no-op when nothing is pending.
+fn poll_rpc_requests(mqtt_client: &SimpleMqttClient, handlers: &HashMap<String, RpcHandler>) -> Result<()> {
+    let (topic, payload) = {
+        let mut inbox = MQTT_INBOX.lock().unwrap();
+        match inbox.as_ref() {
+            Some((topic, _)) if topic.starts_with(RPC_REQUEST_PREFIX) => inbox.take().unwrap(),
+            _ => return Ok(()),
+        }
+    };
+
+    let request_id = &topic[RPC_REQUEST_PREFIX.len()..];
+    let request: Value = match serde_json::from_slice(&payload) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Malformed RPC request on {}: {:?}", topic, e);
+            return Ok(());
+        }
+    };
+
+    let method = request.get("method").and_then(Value::as_str).unwrap_or_default();
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let response = match handlers.get(method) {
+        Some(handler) => handler(params),
+        None => {
+            warn!("No RPC handler registered for method '{}'", method);
+            json!({ "error": format!("unknown method '{}'", method) })
+        }
+    };
+
+    mqtt_client.publish(
+        &format!("v1/devices/me/rpc/response/{}", request_id),
+        &response.to_string(),
+    )?;
+    info!("📟 RPC {} -> {}", method, response);
     Ok(())
 }
 
+// ====================================================
+// 🕒 SNTP time synchronization
+// ====================================================
+const SNTP_SYNC_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Set once the system clock has been synced past the fresh-boot epoch, so
+// telemetry functions know whether `ts`/`client_ts` are trustworthy.
+static TIME_SYNCED: AtomicBool = AtomicBool::new(false);
+
+// A cold ESP32 RTC starts at/near the UNIX epoch, so a coarse "is this
+// plausibly a real date" check is enough to tell a synced clock from an
+// un-synced one without pulling in a full calendar library.
+fn looks_like_real_time() -> bool {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let year = 1970 + secs / (365 * 24 * 3600);
+    year > 2023
+}
+
+// Start the SNTP client against pool.ntp.org and block (with a timeout) until
+// the clock is actually set. The returned handle must be kept alive for as
+// long as periodic re-sync is wanted.
+fn sync_time() -> Result<EspSntp<'static>> {
+    let sntp = EspSntp::new_default()?;
+    info!("⏳ Waiting for SNTP time sync...");
+
+    let start = Instant::now();
+    loop {
+        if sntp.get_sync_status() == SyncStatus::Completed && looks_like_real_time() {
+            break;
+        }
+        if start.elapsed() > SNTP_SYNC_TIMEOUT {
+            return Err(anyhow::anyhow!("Timed out waiting for SNTP sync"));
+        }
+        esp_idf_hal::delay::FreeRtos::delay_ms(500);
+    }
+
+    TIME_SYNCED.store(true, Ordering::Relaxed);
+    info!("🕒 System clock synced via SNTP");
+    Ok(sntp)
+}
+
+// ====================================================
+// 📥 Offline telemetry outbox (NVS-backed)
+// ====================================================
+const OUTBOX_NAMESPACE: &str = "outbox";
+const OUTBOX_CAPACITY: u64 = 64;
+const OUTBOX_ENTRY_MAX_LEN: usize = 512;
+
+// Ring buffer of not-yet-delivered telemetry payloads, persisted in NVS so a
+// Wi-Fi/broker outage (or a reboot during one) doesn't lose readings. `head`/
+// `tail` are the oldest/next-free sequence numbers; both only ever increase,
+// so `tail` doubles as the monotonically increasing sequence number stamped
+// onto each queued payload, and slot reuse is `seq % OUTBOX_CAPACITY`.
+struct TelemetryOutbox {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl TelemetryOutbox {
+    fn new(nvs_partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(nvs_partition, OUTBOX_NAMESPACE, true)?,
+        })
+    }
+
+    fn head(&self) -> u64 {
+        self.nvs.get_u64("head").ok().flatten().unwrap_or(0)
+    }
+
+    fn tail(&self) -> u64 {
+        self.nvs.get_u64("tail").ok().flatten().unwrap_or(0)
+    }
+
+    fn slot_key(seq: u64) -> String {
+        format!("q{}", seq % OUTBOX_CAPACITY)
+    }
+
+    // Queue a telemetry payload for later delivery, stamping it with the
+    // next sequence number and dropping the oldest entry once the ring
+    // buffer is full. Rejects (rather than silently truncating) a payload
+    // that wouldn't round-trip through `flush`'s fixed-size read buffer.
+    fn push(&mut self, mut payload: Value) -> Result<u64> {
+        let mut head = self.head();
+        let mut tail = self.tail();
+        let seq = tail;
+
+        if let Value::Object(ref mut map) = payload {
+            map.insert("seq".to_string(), json!(seq));
+        }
+        let payload_str = payload.to_string();
+        if payload_str.len() + 1 > OUTBOX_ENTRY_MAX_LEN {
+            warn!(
+                "⚠️ Telemetry payload ({} bytes) exceeds outbox entry limit ({} bytes); dropping seq {}",
+                payload_str.len(),
+                OUTBOX_ENTRY_MAX_LEN,
+                seq
+            );
+            return Err(anyhow::anyhow!("telemetry payload too large to queue"));
+        }
+
+        if tail - head >= OUTBOX_CAPACITY {
+            let _ = self.nvs.remove(&Self::slot_key(head));
+            head += 1;
+            self.nvs.set_u64("head", head)?;
+        }
+
+        self.nvs.set_str(&Self::slot_key(seq), &payload_str)?;
+        tail += 1;
+        self.nvs.set_u64("tail", tail)?;
+        Ok(seq)
+    }
+
+    // Resend queued entries oldest-first, removing each only once
+    // esp_mqtt_client_publish has taken it at QoS 1 (returned a valid
+    // message id); the broker's PUBACK is not waited for. Stops at the
+    // first failure so ordering is preserved across retries.
+    fn flush(&mut self, mqtt_client: &SimpleMqttClient) -> u64 {
+        let connected = MQTT_CONN_STATE
+            .lock()
+            .map(|s| *s == MqttConnState::Connected)
+            .unwrap_or(false);
+        if !connected {
+            return 0;
+        }
+
+        let mut head = self.head();
+        let tail = self.tail();
+        let mut flushed = 0u64;
+
+        while head < tail {
+            let key = Self::slot_key(head);
+            let mut buf = [0u8; OUTBOX_ENTRY_MAX_LEN];
+            let payload = match self.nvs.get_str(&key, &mut buf) {
+                Ok(Some(payload)) => payload.to_string(),
+                _ => {
+                    // Slot missing/corrupt; skip past it rather than get stuck.
+                    head += 1;
+                    let _ = self.nvs.set_u64("head", head);
+                    continue;
+                }
+            };
+
+            match mqtt_client.publish("v1/devices/me/telemetry", &payload) {
+                Ok(()) => {
+                    let _ = self.nvs.remove(&key);
+                    head += 1;
+                    let _ = self.nvs.set_u64("head", head);
+                    flushed += 1;
+                }
+                Err(_) => break,
+            }
+        }
+
+        flushed
+    }
+}
+
+// Publish straight to ThingsBoard when connected; otherwise (or if the
+// publish itself fails) queue the payload in the NVS outbox so it isn't
+// lost across a transient Wi-Fi/broker outage.
+fn publish_or_enqueue(mqtt_client: &SimpleMqttClient, outbox: &mut TelemetryOutbox, payload: Value) -> Result<()> {
+    let connected = MQTT_CONN_STATE
+        .lock()
+        .map(|s| *s == MqttConnState::Connected)
+        .unwrap_or(false);
+
+    if connected {
+        let payload_str = payload.to_string();
+        match mqtt_client.publish("v1/devices/me/telemetry", &payload_str) {
+            Ok(()) => {
+                info!("Data sent to ThingsBoard: {}", payload_str);
+                return Ok(());
+            }
+            Err(e) => warn!("Publish failed ({:?}); queuing for later delivery", e),
+        }
+    }
+
+    let seq = outbox.push(payload)?;
+    info!("📥 Queued telemetry as seq {} for later delivery", seq);
+    Ok(())
+}
+
+// Build the `{"temperature_<rom>": value, ...}` keys shared by all the
+// telemetry formats below, one entry per DS18B20 on the bus.
+fn keyed_temperatures(readings: &HashMap<String, f32>) -> serde_json::Map<String, Value> {
+    readings
+        .iter()
+        .map(|(rom, temp)| (format!("temperature_{}", rom), json!(temp)))
+        .collect()
+}
+
+// Function to send temperature data to ThingsBoard
+fn send_telemetry(
+    mqtt_client: &SimpleMqttClient,
+    outbox: &mut TelemetryOutbox,
+    readings: &HashMap<String, f32>,
+) -> Result<()> {
+    publish_or_enqueue(mqtt_client, outbox, Value::Object(keyed_temperatures(readings)))
+}
+
 // ====================================================
 // 🕒 Function to send telemetry with timestamp and client_ts
 // ====================================================
-fn send_telemetry_with_timestamp(mqtt_client: &SimpleMqttClient, temperature: f32) -> Result<()> {
+fn send_telemetry_with_timestamp(
+    mqtt_client: &SimpleMqttClient,
+    outbox: &mut TelemetryOutbox,
+    readings: &HashMap<String, f32>,
+) -> Result<()> {
     use std::time::{SystemTime, UNIX_EPOCH};
 
     // Dapatkan waktu sekarang (timestamp dalam milidetik)
@@ -153,44 +836,48 @@ fn send_telemetry_with_timestamp(mqtt_client: &SimpleMqttClient, temperature: f3
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_millis();
+    let synced = TIME_SYNCED.load(Ordering::Relaxed);
 
-    // Buat payload JSON dengan timestamp, temperature, dan client_ts
-    let payload = json!({
-        "ts": now,             // timestamp epoch (ms)
-        "values": {
-            "temperature": temperature,
-            "client_ts": now   // client timestamp sama dengan waktu lokal
-        }
-    })
-    .to_string();
+    // Buat payload JSON dengan timestamp, temperature per-sensor, dan client_ts.
+    // Skip "ts" while the clock isn't synced yet so ThingsBoard doesn't
+    // ingest the point dated to the 1970 epoch.
+    let mut values = keyed_temperatures(readings);
+    values.insert("client_ts".to_string(), json!(now)); // client timestamp sama dengan waktu lokal
+    values.insert("time_synced".to_string(), json!(synced));
 
-    // Kirim payload ke ThingsBoard
-    mqtt_client.publish("v1/devices/me/telemetry", &payload)?;
-    info!("📡 Data sent with timestamp -> {}", payload);
-    Ok(())
+    let mut payload = serde_json::Map::new();
+    if synced {
+        payload.insert("ts".to_string(), json!(now));
+    }
+    payload.insert("values".to_string(), Value::Object(values));
+
+    publish_or_enqueue(mqtt_client, outbox, Value::Object(payload))
 }
 
 // ====================================================
 // 🕒 Tambahan: Kirim format langsung timestamp, temperature, client_ts
 // ====================================================
-fn send_telemetry_full_format(mqtt_client: &SimpleMqttClient, temperature: f32) -> Result<()> {
+fn send_telemetry_full_format(
+    mqtt_client: &SimpleMqttClient,
+    outbox: &mut TelemetryOutbox,
+    readings: &HashMap<String, f32>,
+) -> Result<()> {
     use std::time::{SystemTime, UNIX_EPOCH};
 
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_millis();
+    let synced = TIME_SYNCED.load(Ordering::Relaxed);
 
-    let payload = json!({
-        "timestamp": now,
-        "temperature": temperature,
-        "client_ts": now
-    })
-    .to_string();
+    let mut payload = keyed_temperatures(readings);
+    if synced {
+        payload.insert("timestamp".to_string(), json!(now));
+    }
+    payload.insert("client_ts".to_string(), json!(now));
+    payload.insert("time_synced".to_string(), json!(synced));
 
-    mqtt_client.publish("v1/devices/me/telemetry", &payload)?;
-    info!("📡 Full-format data sent to ThingsBoard -> {}", payload);
-    Ok(())
+    publish_or_enqueue(mqtt_client, outbox, Value::Object(payload))
 }
 
 // Function to connect to Wi-Fi
@@ -212,6 +899,142 @@ fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
     Ok(())
 }
 
+// ====================================================
+// 🌡️ DS18B20 bus scan and per-device reads
+// ====================================================
+type Ds18b20OneWire = OneWire<PinDriver<'static, Gpio6, InputOutputOD>>;
+
+// Walk the whole 1-Wire bus with the search algorithm (instead of skip-ROM)
+// so every DS18B20 sharing GPIO6 is discovered individually.
+fn scan_ds18b20_devices(one_wire: &mut Ds18b20OneWire, delay: &mut Delay) -> Vec<[u8; 8]> {
+    let mut roms = Vec::new();
+    let mut search = DeviceSearch::new();
+    loop {
+        match one_wire.search_next(&mut search, delay) {
+            Ok(Some(device)) => roms.push(device.address),
+            Ok(None) => break,
+            Err(e) => {
+                error!("❌ 1-Wire search error: {:?}", e);
+                break;
+            }
+        }
+    }
+    roms
+}
+
+// Address a single sensor with Match-ROM (0x55 + its 64-bit address) instead
+// of skip-ROM, so readings aren't corrupted when multiple sensors share the
+// bus.
+fn read_ds18b20(one_wire: &mut Ds18b20OneWire, delay: &mut Delay, rom: &[u8; 8]) -> Result<[u8; 9]> {
+    one_wire
+        .reset(delay)
+        .map_err(|e| anyhow::anyhow!("1-Wire reset failed: {:?}", e))?;
+    let mut convert_cmd = [0u8; 10];
+    convert_cmd[0] = 0x55;
+    convert_cmd[1..9].copy_from_slice(rom);
+    convert_cmd[9] = 0x44;
+    one_wire
+        .write_bytes(delay, &convert_cmd)
+        .map_err(|e| anyhow::anyhow!("Match ROM/convert failed: {:?}", e))?;
+    delay.delay_ms(750);
+
+    one_wire
+        .reset(delay)
+        .map_err(|e| anyhow::anyhow!("1-Wire reset failed: {:?}", e))?;
+    let mut read_cmd = [0u8; 10];
+    read_cmd[0] = 0x55;
+    read_cmd[1..9].copy_from_slice(rom);
+    read_cmd[9] = 0xBE;
+    one_wire
+        .write_bytes(delay, &read_cmd)
+        .map_err(|e| anyhow::anyhow!("Match ROM/read scratchpad failed: {:?}", e))?;
+
+    let mut data = [0u8; 9];
+    one_wire
+        .read_bytes(delay, &mut data)
+        .map_err(|e| anyhow::anyhow!("Scratchpad read failed: {:?}", e))?;
+    Ok(data)
+}
+
+const DS18B20_MAX_RETRIES: u32 = 3;
+// Raw scratchpad bytes 0..1 read as 0x0550 (85°C) before the sensor has
+// completed its first real conversion — reserved/power-on value, not a
+// measurement.
+const DS18B20_POWER_ON_RESET_RAW: i16 = 0x0550;
+
+// Dallas/Maxim CRC-8 (poly x^8+x^5+x^4+1, init 0) over scratchpad bytes 0..8,
+// checked against the scratchpad's 9th byte to catch a glitched 1-Wire
+// transaction before its plausible-but-wrong temperature gets published.
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in bytes {
+        let mut byte = byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+// Wraps read_ds18b20 with CRC validation and retry: a bad CRC or the
+// reserved power-on value is logged with the raw scratchpad and retried,
+// and a sensor fault is reported once retries are exhausted.
+fn read_ds18b20_validated(
+    one_wire: &mut Ds18b20OneWire,
+    delay: &mut Delay,
+    rom: &[u8; 8],
+) -> Result<Celsius> {
+    let mut last_err = anyhow::anyhow!("sensor fault: no attempts made");
+    for attempt in 1..=DS18B20_MAX_RETRIES {
+        let data = match read_ds18b20(one_wire, delay, rom) {
+            Ok(data) => data,
+            Err(e) => {
+                last_err = e;
+                continue;
+            }
+        };
+
+        if crc8(&data[0..8]) != data[8] {
+            warn!(
+                "⚠️ [{}] Scratchpad CRC mismatch on attempt {}/{}, raw: {:?}",
+                rom_to_hex(rom),
+                attempt,
+                DS18B20_MAX_RETRIES,
+                data
+            );
+            last_err = anyhow::anyhow!("scratchpad CRC mismatch");
+            continue;
+        }
+
+        let temp_raw = (data[1] as i16) << 8 | data[0] as i16;
+        if temp_raw == DS18B20_POWER_ON_RESET_RAW {
+            warn!(
+                "⚠️ [{}] Reserved power-on reset value (85°C) on attempt {}/{}, raw: {:?}",
+                rom_to_hex(rom),
+                attempt,
+                DS18B20_MAX_RETRIES,
+                data
+            );
+            last_err = anyhow::anyhow!("power-on reset value");
+            continue;
+        }
+
+        return Ok(Celsius(temp_raw as f32 / 16.0));
+    }
+
+    Err(anyhow::anyhow!(
+        "sensor fault: [{}] no valid reading after {} attempts ({})",
+        rom_to_hex(rom),
+        DS18B20_MAX_RETRIES,
+        last_err
+    ))
+}
+
 // ====================================================
 // 🧠 MAIN PROGRAM
 // ====================================================
@@ -223,6 +1046,7 @@ fn main() -> Result<()> {
     let peripherals = Peripherals::take().unwrap();
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let nvs = EspDefaultNvsPartition::take().unwrap();
+    let mut outbox = TelemetryOutbox::new(nvs.clone())?;
 
     let mut wifi = BlockingWifi::wrap(
         EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
@@ -241,6 +1065,13 @@ fn main() -> Result<()> {
     }
 }
 
+    let _sntp = loop {
+        match sync_time() {
+            Ok(sntp) => break sntp,
+            Err(e) => error!("❌ SNTP sync failed: {:?}. Retrying...", e),
+        }
+    };
+
     let pin_ds18b20 = peripherals.pins.gpio6;
     let pin = PinDriver::input_output_od(pin_ds18b20)?;
     info!("GPIO6 configured for 1-Wire");
@@ -248,12 +1079,34 @@ fn main() -> Result<()> {
     let mut one_wire = OneWire::new(pin, false);
     let mut delay = Delay::new(100);
 
-    info!("Connecting to MQTT broker...");
+    info!("🔍 Scanning for DS18B20 devices on the 1-Wire bus...");
+    let sensor_roms = scan_ds18b20_devices(&mut one_wire, &mut delay);
+    if sensor_roms.is_empty() {
+        warn!("⚠️ No DS18B20 devices found on the 1-Wire bus");
+    } else {
+        info!(
+            "Found {} DS18B20 device(s): {}",
+            sensor_roms.len(),
+            sensor_roms
+                .iter()
+                .map(rom_to_hex)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    info!("Connecting to MQTT broker over TLS...");
     let mqtt_client = match SimpleMqttClient::new(
-        "mqtt://mqtt.thingsboard.cloud:1883",
+        "mqtts://mqtt.thingsboard.cloud:8883",
         "riskalaena",
         "riskalaena",
         "ybo47mjx9o7vcziv9i6p",
+        Transport::Tls {
+            ca_pem: THINGSBOARD_CA_PEM,
+            skip_cn_check: false,
+            client_cert_pem: None,
+            client_key_pem: None,
+        },
     ) {
         Ok(client) => {
             info!("Connected to ThingsBoard MQTT broker");
@@ -265,79 +1118,105 @@ fn main() -> Result<()> {
         }
     };
 
-    if let Err(e) = send_ota_status(&mqtt_client, "myFirmware", "1.0.0", "UPDATED") {
+    if let Err(e) = send_ota_status(&mqtt_client, FW_TITLE, FW_VERSION, "UPDATED") {
         error!("Failed to send OTA status: {:?}", e);
     }
+    if let Err(e) = check_and_apply_ota(&mqtt_client) {
+        error!("OTA check failed: {:?}", e);
+    }
 
-    info!("🔍 Attempting direct temperature reading (skip ROM)...");
-    if let Ok(_) = one_wire.reset(&mut delay) {
-        info!("1-Wire bus reset successful for skip ROM");
-        if let Err(e) = one_wire.write_bytes(&mut delay, &[0xCC, 0x44]) {
-            error!("❌ Failed to send skip ROM/convert command: {:?}", e);
-        } else {
-            delay.delay_ms(750);
-            if let Ok(_) = one_wire.reset(&mut delay) {
-                if let Err(e) = one_wire.write_bytes(&mut delay, &[0xCC, 0xBE]) {
-                    error!("❌ Failed to send read scratchpad command: {:?}", e);
-                } else {
-                    let mut data = [0u8; 9];
-                    if let Err(e) = one_wire.read_bytes(&mut delay, &mut data) {
-                        error!("❌ Failed to read scratchpad: {:?}", e);
-                    } else {
-                        info!("Scratchpad data: {:?}", data);
-                        let temp_raw = (data[1] as i16) << 8 | data[0] as i16;
-                        let temp_celsius = Celsius(temp_raw as f32 / 16.0);
-                        info!("🌡️ Temperature: {:.1} °C", temp_celsius.as_f32());
-                        if let Err(e) = send_telemetry(&mqtt_client, temp_celsius.as_f32()) {
-                            error!("Failed to send telemetry: {:?}", e);
-                        }
-                        if let Err(e) = send_telemetry_with_timestamp(&mqtt_client, temp_celsius.as_f32()) {
-                            error!("Failed to send telemetry with timestamp: {:?}", e);
-                        }
-                        // 🧩 Tambahan kirim format lengkap timestamp, temperature, client_ts
-                        if let Err(e) = send_telemetry_full_format(&mqtt_client, temp_celsius.as_f32()) {
-                            error!("Failed to send full-format telemetry: {:?}", e);
-                        }
-                    }
+    let sample_interval_ms = Arc::new(Mutex::new(3000u64));
+    let last_readings: Arc<Mutex<HashMap<String, f32>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    let mut rpc_handlers: HashMap<String, RpcHandler> = HashMap::new();
+    {
+        let last_readings = last_readings.clone();
+        rpc_handlers.insert(
+            "getTemperature".to_string(),
+            Box::new(move |_params| json!(*last_readings.lock().unwrap())) as RpcHandler,
+        );
+    }
+    {
+        let sample_interval_ms = sample_interval_ms.clone();
+        rpc_handlers.insert(
+            "setInterval".to_string(),
+            Box::new(move |params| match params.as_u64() {
+                Some(ms) => {
+                    *sample_interval_ms.lock().unwrap() = ms;
+                    info!("⏱️ Sampling interval changed to {} ms via RPC", ms);
+                    json!({ "interval_ms": ms })
                 }
-            } else {
-                error!("❌ Failed to reset bus for reading");
-            }
-        }
-    } else {
-        error!("❌ Failed to reset 1-Wire bus for skip ROM");
+                None => json!({ "error": "expected interval in milliseconds" }),
+            }) as RpcHandler,
+        );
     }
+    rpc_handlers.insert(
+        "reboot".to_string(),
+        Box::new(|_params| {
+            warn!("♻️ Reboot requested via RPC");
+            unsafe { esp_restart() };
+        }) as RpcHandler,
+    );
 
-    info!("📊 Starting temperature readings every 3 seconds...");
+    if let Err(e) = mqtt_client.subscribe("v1/devices/me/rpc/request/+") {
+        error!("Failed to subscribe to RPC requests: {:?}", e);
+    }
+
+    // Every startup step (connect, OTA check, RPC setup) has now run; errors
+    // from the OTA check and the RPC subscribe are only logged and do not
+    // stop this. Mark the app valid here, right before the sampling loop, so
+    // a boot that crashes or hangs before reaching the loop still rolls back
+    // to the previous partition on next boot.
+    unsafe {
+        esp_ota_mark_app_valid_cancel_rollback();
+    }
+    info!("✅ App marked valid, rollback cancelled");
+
+    info!("📊 Starting temperature readings (interval adjustable via RPC)...");
     loop {
-        match one_wire.reset(&mut delay) {
-            Ok(_) => {
-                let _ = one_wire.write_bytes(&mut delay, &[0xCC, 0x44]);
-                delay.delay_ms(750);
-                let _ = one_wire.reset(&mut delay);
-                let _ = one_wire.write_bytes(&mut delay, &[0xCC, 0xBE]);
-
-                let mut data = [0u8; 9];
-                if let Ok(_) = one_wire.read_bytes(&mut delay, &mut data) {
-                    let temp_raw = (data[1] as i16) << 8 | data[0] as i16;
-                    let temp_celsius = Celsius(temp_raw as f32 / 16.0);
-                    info!("🌡️ Temperature: {:.1} °C", temp_celsius.as_f32());
-                    if let Err(e) = send_telemetry(&mqtt_client, temp_celsius.as_f32()) {
-                        error!("Failed to send telemetry: {:?}", e);
-                    }
-                    if let Err(e) = send_telemetry_with_timestamp(&mqtt_client, temp_celsius.as_f32()) {
-                        error!("Failed to send telemetry with timestamp: {:?}", e);
-                    }
-                    // 🧩 Tambahan kirim format lengkap timestamp, temperature, client_ts
-                    if let Err(e) = send_telemetry_full_format(&mqtt_client, temp_celsius.as_f32()) {
-                        error!("Failed to send full-format telemetry: {:?}", e);
+        if let Err(e) = poll_rpc_requests(&mqtt_client, &rpc_handlers) {
+            error!("Failed to handle RPC request: {:?}", e);
+        }
+
+        if let Err(e) = poll_shared_attribute_updates(&mqtt_client) {
+            error!("Failed to handle shared attribute update: {:?}", e);
+        }
+
+        let flushed = outbox.flush(&mqtt_client);
+        if flushed > 0 {
+            info!("📤 Flushed {} queued telemetry record(s) from the outbox", flushed);
+        }
+
+        if sensor_roms.is_empty() {
+            warn!("⚠️ No DS18B20 devices to sample");
+        } else {
+            let mut readings: HashMap<String, f32> = HashMap::new();
+            for rom in &sensor_roms {
+                let rom_hex = rom_to_hex(rom);
+                match read_ds18b20_validated(&mut one_wire, &mut delay, rom) {
+                    Ok(temp_celsius) => {
+                        info!("🌡️ [{}] Temperature: {:.1} °C", rom_hex, temp_celsius.as_f32());
+                        readings.insert(rom_hex, temp_celsius.as_f32());
                     }
+                    Err(e) => error!("❌ Failed to read sensor {}: {:?}", rom_hex, e),
                 }
             }
-            Err(e) => {
-                error!("❌ Error during temperature read: {:?}", e);
+
+            if !readings.is_empty() {
+                *last_readings.lock().unwrap() = readings.clone();
+                if let Err(e) = send_telemetry(&mqtt_client, &mut outbox, &readings) {
+                    error!("Failed to send telemetry: {:?}", e);
+                }
+                if let Err(e) = send_telemetry_with_timestamp(&mqtt_client, &mut outbox, &readings) {
+                    error!("Failed to send telemetry with timestamp: {:?}", e);
+                }
+                // 🧩 Tambahan kirim format lengkap timestamp, temperature, client_ts
+                if let Err(e) = send_telemetry_full_format(&mqtt_client, &mut outbox, &readings) {
+                    error!("Failed to send full-format telemetry: {:?}", e);
+                }
             }
         }
-        delay.delay_ms(3000);
+
+        delay.delay_ms(*sample_interval_ms.lock().unwrap() as u32);
     }
 }